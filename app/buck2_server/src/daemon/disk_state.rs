@@ -9,6 +9,8 @@
 
 use std::collections::HashMap;
 use std::sync::Arc;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
 
 use allocative::Allocative;
 use anyhow::Context;
@@ -16,6 +18,7 @@ use buck2_common::invocation_paths::InvocationPaths;
 use buck2_common::legacy_configs::LegacyBuckConfig;
 use buck2_core::fs::fs_util;
 use buck2_core::fs::paths::abs_norm_path::AbsNormPath;
+use buck2_core::fs::paths::abs_norm_path::AbsNormPathBuf;
 use buck2_core::fs::paths::file_name::FileName;
 use buck2_core::fs::project::ProjectRoot;
 use buck2_core::rollout_percentage::RolloutPercentage;
@@ -28,15 +31,280 @@ use buck2_execute_impl::materializers::sqlite::MaterializerStateSqliteDb;
 use buck2_execute_impl::materializers::sqlite::DB_SCHEMA_VERSION;
 use chrono::Utc;
 use derive_more::Display;
+use dupe::Dupe;
+
+/// What a cache backed by an on-disk SQLite DB should do when the DB can't be
+/// opened or recreated (e.g. the disk is full or the parent directory isn't
+/// writable). This is the last line of defense after the open-retry and
+/// delete-and-recreate steps in `recover_disk_cache` have both failed.
+#[derive(Clone, Copy, Dupe, Debug, PartialEq, Eq, Allocative, Display)]
+pub enum CacheFailureStrategy {
+    /// Keep a separate DB, opened by the cache's `open_in_memory` fallback,
+    /// for the rest of this process; reads and writes within the process
+    /// behave normally. For the materializer state cache this DB is a SQLite
+    /// file at `materializer_state.in_memory_fallback` next to the primary DB
+    /// path, not an in-memory connection.
+    #[display(fmt = "in_memory")]
+    InMemory,
+    /// Silently drop every write and answer every read as empty. Cheapest
+    /// option when losing this particular cache is harmless to correctness.
+    #[display(fmt = "black_hole")]
+    BlackHole,
+    /// Return the last open or delete error from recovery instead of a DB.
+    /// No DB is produced, so there is no cache object for later operations
+    /// to run against.
+    #[display(fmt = "error")]
+    Error,
+}
+
+impl std::str::FromStr for CacheFailureStrategy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "in_memory" => Ok(Self::InMemory),
+            "black_hole" => Ok(Self::BlackHole),
+            "error" => Ok(Self::Error),
+            _ => Err(anyhow::anyhow!(
+                "Invalid value for `cache_failure_strategy`: `{}`. Expected one of \
+                 `in_memory`, `black_hole`, `error`",
+                s
+            )),
+        }
+    }
+}
+
+/// How a disk-backed cache's DB was brought up. Surfaced alongside
+/// [`MaterializerStateIdentity`] so callers and logs can tell a clean load
+/// apart from one that required recovering from a corrupt file on disk.
+#[derive(Clone, Copy, Dupe, Debug, PartialEq, Eq, Allocative, Display)]
+pub enum CacheRecoveryOutcome {
+    /// `open` succeeded within [`MAX_OPEN_ATTEMPTS`] tries, without deleting
+    /// anything first. Whether the DB's stored state loaded is reported
+    /// separately.
+    #[display(fmt = "clean")]
+    Clean,
+    /// The existing on-disk DB was unreadable; it was deleted and a fresh
+    /// schema was created in its place.
+    #[display(fmt = "recovered-fresh")]
+    RecoveredFresh,
+    /// Delete-and-recreate also failed; this cache is running under its
+    /// configured [`CacheFailureStrategy`] for the rest of this process.
+    #[display(fmt = "fallback({})", _0)]
+    Fallback(CacheFailureStrategy),
+}
+
+/// Added to upstream's `DB_SCHEMA_VERSION` to form the materializer state
+/// cache's schema version, so state stored under the previous version is not
+/// loaded on the next startup. The on-disk row format, defined by the sqlite
+/// module, is unchanged: it has no `second_ambiguous` column, and no
+/// `TruncatedTimestamp` is persisted in it.
+const MTIME_SECOND_AMBIGUOUS_SCHEMA_BUMP: u64 = 1;
+
+/// A filesystem mtime truncated to the (seconds, nanoseconds) resolution
+/// actually persisted on disk, plus the dirstate-v2-style ambiguity bit: if
+/// `seconds` equals the wall-clock second in which we observed and recorded
+/// this timestamp, a later write to the same file in that same second would
+/// produce an identical on-disk mtime, so we can't prove the file didn't
+/// change after we looked. `second_ambiguous` records that case so validation
+/// never trusts the timestamp alone when it's set.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Allocative)]
+pub struct TruncatedTimestamp {
+    pub seconds: u64,
+    pub nanoseconds: u32,
+    pub second_ambiguous: bool,
+}
+
+impl TruncatedTimestamp {
+    /// Truncates `mtime` to on-disk resolution, marking it ambiguous if its
+    /// seconds component equals `observed_at`'s, i.e. it was (or might have
+    /// been) written in the same wall-clock second we're recording it in.
+    pub fn new(mtime: SystemTime, observed_at: SystemTime) -> anyhow::Result<Self> {
+        let since_epoch = mtime
+            .duration_since(UNIX_EPOCH)
+            .context("mtime is before the UNIX epoch")?;
+        let observed_since_epoch = observed_at
+            .duration_since(UNIX_EPOCH)
+            .context("observed_at is before the UNIX epoch")?;
+        Ok(Self {
+            seconds: since_epoch.as_secs(),
+            nanoseconds: since_epoch.subsec_nanos(),
+            second_ambiguous: since_epoch.as_secs() == observed_since_epoch.as_secs(),
+        })
+    }
+
+    /// Whether this timestamp alone is enough to prove the artifact is
+    /// unchanged. An ambiguous timestamp never is.
+    pub fn is_trustworthy(&self) -> bool {
+        !self.second_ambiguous
+    }
+}
+
+/// Result of comparing an earlier `TruncatedTimestamp` of a path against a
+/// fresh one. Neither is read from or stored in `MaterializerStateSqliteDb`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MtimeValidation {
+    /// The recorded and on-disk mtimes match and neither is ambiguous: the
+    /// artifact can be trusted as unchanged without reading its contents.
+    Clean,
+    /// The mtimes differ, or either one is ambiguous, so the only way to know
+    /// whether the artifact changed is a content/digest check.
+    NeedsDigestCheck,
+}
+
+/// Decides whether `recorded` (an earlier stat) still matches `on_disk`
+/// (freshly stat'd), per the rule above: same-second writes are never trusted
+/// on mtime alone, even if the truncated values happen to be equal. Its only
+/// caller compares two stats of the materializer state DB path; the mtimes of
+/// materialized artifacts are not checked with it.
+pub fn validate_mtime(
+    recorded: &TruncatedTimestamp,
+    on_disk: &TruncatedTimestamp,
+) -> MtimeValidation {
+    if recorded.is_trustworthy() && on_disk.is_trustworthy() && recorded == on_disk {
+        MtimeValidation::Clean
+    } else {
+        MtimeValidation::NeedsDigestCheck
+    }
+}
+
+/// Truncated mtime of the materializer state DB path (the `materializer_state`
+/// directory, not the `db.sqlite` file inside it), or `None` if it doesn't
+/// exist yet or can't be stat'd. Used by
+/// `maybe_initialize_materializer_sqlite_db` to tell a DB load that actually
+/// reflects the file we just looked at apart from one that raced a concurrent
+/// writer between our first look and the moment open/recovery finished.
+fn stat_materializer_state_mtime(path: &AbsNormPathBuf) -> Option<TruncatedTimestamp> {
+    let observed_at = SystemTime::now();
+    let mtime = std::fs::metadata(path).ok()?.modified().ok()?;
+    TruncatedTimestamp::new(mtime, observed_at).ok()
+}
+
+/// Number of times to try opening the existing on-disk DB, the first try
+/// included (e.g. to get past a transient lock), before concluding it's
+/// corrupt and needs to be recreated.
+const MAX_OPEN_ATTEMPTS: u32 = 2;
+
+/// Implements the recovery policy described on [`CacheFailureStrategy`]: try
+/// `open` up to [`MAX_OPEN_ATTEMPTS`] times, then `delete` and try `open`
+/// once more on repeated failure, and only fall back to `failure_strategy` if
+/// that also fails — including when `delete` itself is what fails, since a
+/// disk-full or permission-denied delete is just another way "delete and
+/// recreate" can fail and shouldn't crash startup out from under a failure
+/// strategy the user configured precisely to avoid that. This is generic over
+/// `open`/`delete`/`open_in_memory` (rather than calling
+/// `MaterializerStateSqliteDb::initialize` and `fs::remove_path_recursive`
+/// directly) so the policy itself can be unit tested without a real SQLite
+/// file.
+async fn recover_disk_cache<T, Open, OpenFut, Delete, DeleteFut, OpenInMemory, OpenInMemoryFut>(
+    mut open: Open,
+    delete: Delete,
+    open_in_memory: OpenInMemory,
+    failure_strategy: CacheFailureStrategy,
+) -> anyhow::Result<(Option<T>, CacheRecoveryOutcome)>
+where
+    Open: FnMut() -> OpenFut,
+    OpenFut: std::future::Future<Output = anyhow::Result<T>>,
+    Delete: FnOnce() -> DeleteFut,
+    DeleteFut: std::future::Future<Output = anyhow::Result<()>>,
+    OpenInMemory: FnOnce() -> OpenInMemoryFut,
+    OpenInMemoryFut: std::future::Future<Output = anyhow::Result<T>>,
+{
+    let mut last_err = None;
+    for _ in 0..MAX_OPEN_ATTEMPTS {
+        match open().await {
+            Ok(v) => return Ok((Some(v), CacheRecoveryOutcome::Clean)),
+            Err(e) => last_err = Some(e),
+        }
+    }
+
+    match delete().await {
+        Ok(()) => match open().await {
+            Ok(v) => return Ok((Some(v), CacheRecoveryOutcome::RecoveredFresh)),
+            Err(e) => last_err = Some(e),
+        },
+        Err(e) => last_err = Some(e),
+    }
+
+    match failure_strategy {
+        CacheFailureStrategy::Error => Err(last_err
+            .unwrap_or_else(|| anyhow::anyhow!("disk cache unavailable"))
+            .context(
+                "exhausted open-retry and delete-and-recreate; \
+                 failing per the configured cache failure strategy",
+            )),
+        // Unlike `BlackHole`, `InMemory` promises reads/writes keep behaving
+        // normally for the rest of this process, so it needs an actual usable
+        // connection rather than `None`.
+        CacheFailureStrategy::InMemory => Ok((
+            Some(
+                open_in_memory()
+                    .await
+                    .context("failed to open in-memory fallback for disk cache")?,
+            ),
+            CacheRecoveryOutcome::Fallback(failure_strategy),
+        )),
+        CacheFailureStrategy::BlackHole => {
+            Ok((None, CacheRecoveryOutcome::Fallback(failure_strategy)))
+        }
+    }
+}
+
+/// Describes one on-disk SQLite-backed cache living under the project's cache
+/// directory (`buck-out/v2/cache/<name>`). Each cache registers itself here
+/// instead of duplicating init/teardown/versioning logic: `delete_unknown_disk_state`
+/// derives its list of known directories from this registry, and a cache's
+/// schema version and failure-recovery strategy travel with it rather than
+/// being threaded through by hand at each call site.
+pub(crate) struct DiskCacheDescriptor {
+    /// Directory name under the cache root, e.g. `materializer_state`.
+    pub name: &'static FileName,
+    /// Schema version for this cache's on-disk format. Bumping this forces a
+    /// fresh DB on next startup.
+    pub db_schema_version: u64,
+    /// What to do if this cache's DB can't be opened or recreated.
+    pub failure_strategy: CacheFailureStrategy,
+}
+
+/// The single source of truth for the materializer state cache's descriptor.
+/// Both `registered_disk_caches` (consumed by `delete_unknown_disk_state`) and
+/// `maybe_initialize_materializer_sqlite_db` go through this, so the name,
+/// schema version, and failure strategy used to open the DB are guaranteed to
+/// match the ones used to decide whether its directory is stale.
+fn materializer_state_cache_descriptor(options: &DiskStateOptions) -> DiskCacheDescriptor {
+    DiskCacheDescriptor {
+        name: FileName::unchecked_new("materializer_state"),
+        db_schema_version: DB_SCHEMA_VERSION + MTIME_SECOND_AMBIGUOUS_SCHEMA_BUMP,
+        failure_strategy: options.cache_failure_strategy,
+    }
+}
+
+/// Returns the descriptors of all disk caches buck2 currently knows about.
+/// Adding a new on-disk cache (a dep file cache, a command-hash cache, ...)
+/// is a matter of registering a descriptor here, not duplicating the
+/// init/teardown/versioning logic this module already has for the
+/// materializer state DB.
+pub(crate) fn registered_disk_caches(options: &DiskStateOptions) -> Vec<DiskCacheDescriptor> {
+    vec![materializer_state_cache_descriptor(options)]
+}
 
 #[derive(Allocative)]
 pub struct DiskStateOptions {
     pub sqlite_materializer_state: bool,
-    // In future, this will include the config for dep files on disk
+    /// What to do if the materializer state DB can't be opened or recreated.
+    pub cache_failure_strategy: CacheFailureStrategy,
+    // In future, this will include the config for a dep file cache, registered
+    // as its own `DiskCacheDescriptor` alongside the materializer state one.
 }
 
 #[derive(Display, Allocative)]
-pub struct MaterializerStateIdentity(String);
+#[display(fmt = "{}", identity)]
+pub struct MaterializerStateIdentity {
+    identity: String,
+    /// Whether this DB loaded cleanly, was recovered from corruption, or is
+    /// running under a failure-strategy fallback for this process.
+    pub recovery: CacheRecoveryOutcome,
+}
 
 impl DiskStateOptions {
     pub fn new(
@@ -51,12 +319,50 @@ impl DiskStateOptions {
             .parse::<RolloutPercentage>("buck2", "sqlite_materializer_state")?
             .unwrap_or_else(RolloutPercentage::never)
             .roll();
+        let cache_failure_strategy = root_config
+            .parse::<CacheFailureStrategy>("buck2", "sqlite_cache_failure_strategy")?
+            .unwrap_or(CacheFailureStrategy::InMemory);
         Ok(Self {
             sqlite_materializer_state,
+            cache_failure_strategy,
         })
     }
 }
 
+/// Handle to a materializer SQLite DB whose connection open (and any schema
+/// migration) was kicked off on a background task rather than inline on the
+/// daemon startup path. Startup continues immediately after getting this
+/// handle; the first caller that actually needs the DB awaits [`Self::join`],
+/// which is a no-op wait if the background task already finished.
+pub(crate) struct MaterializerStateSqliteDbHandle {
+    task: tokio::task::JoinHandle<
+        anyhow::Result<(
+            Option<MaterializerStateSqliteDb>,
+            MaterializerStateIdentity,
+            Option<MaterializerState>,
+        )>,
+    >,
+}
+
+impl MaterializerStateSqliteDbHandle {
+    /// Awaits DB open/migration if it hasn't finished yet, then hands back the
+    /// DB, its identity and the loaded materializer state (if any). The DB is
+    /// `None` only when recovery fell back to
+    /// [`CacheFailureStrategy::BlackHole`]; under
+    /// [`CacheFailureStrategy::InMemory`] it is the fallback DB.
+    pub(crate) async fn join(
+        self,
+    ) -> anyhow::Result<(
+        Option<MaterializerStateSqliteDb>,
+        MaterializerStateIdentity,
+        Option<MaterializerState>,
+    )> {
+        self.task
+            .await
+            .context("Materializer sqlite db initialization task panicked")?
+    }
+}
+
 pub(crate) async fn maybe_initialize_materializer_sqlite_db(
     options: &DiskStateOptions,
     paths: &InvocationPaths,
@@ -65,27 +371,36 @@ pub(crate) async fn maybe_initialize_materializer_sqlite_db(
     deferred_materializer_configs: &DeferredMaterializerConfigs,
     fs: ProjectRoot,
     digest_config: DigestConfig,
-) -> anyhow::Result<(
-    Option<(MaterializerStateSqliteDb, MaterializerStateIdentity)>,
-    Option<MaterializerState>,
-)> {
+) -> anyhow::Result<Option<MaterializerStateSqliteDbHandle>> {
     if !options.sqlite_materializer_state {
         // When sqlite materializer state is disabled, we should always delete the materializer state db.
         // Otherwise, artifacts in buck-out will diverge from the state stored in db.
         io_executor
             .execute_io_inline(|| fs.remove_path_recursive(&paths.materializer_state_path()))
             .await?;
-        return Ok((None, None));
+        return Ok(None);
     }
 
     let timestamp_key = "timestamp_on_initialization";
 
     let mut metadata = buck2_events::metadata::collect();
     let timestamp_on_initialization = Utc::now().to_rfc3339();
-    metadata.insert(timestamp_key.to_owned(), timestamp_on_initialization);
+    metadata.insert(timestamp_key.to_owned(), timestamp_on_initialization.clone());
+
+    // Go through the same descriptor `delete_unknown_disk_state` uses, so the
+    // schema version and failure strategy this DB is opened with can never
+    // drift from the ones the registry advertises for it.
+    let cache = materializer_state_cache_descriptor(options);
 
+    // `db_schema_version` includes `MTIME_SECOND_AMBIGUOUS_SCHEMA_BUMP`, so
+    // state stored by a build without it is not loaded. The `validate_mtime`
+    // check below does not depend on this: it compares two stats taken during
+    // this initialization.
     let mut versions = HashMap::from([
-        ("schema_version".to_owned(), DB_SCHEMA_VERSION.to_string()),
+        (
+            "schema_version".to_owned(),
+            cache.db_schema_version.to_string(),
+        ),
         (
             "defer_write_actions".to_owned(),
             deferred_materializer_configs
@@ -102,33 +417,129 @@ pub(crate) async fn maybe_initialize_materializer_sqlite_db(
         versions.insert("hostname".to_owned(), hostname.to_owned());
     }
 
-    // Most things in the rest of `metadata` should go in the metadata sqlite table.
-    // TODO(scottcao): Narrow down what metadata we need and and insert them into the
-    // metadata table before a feature rollout.
-    let (mut db, load_result) = MaterializerStateSqliteDb::initialize(
-        paths.materializer_state_path(),
-        versions,
-        metadata,
-        io_executor,
-        digest_config,
-    )
-    .await?;
-
-    let identity = db
-        .created_by_table()
-        .get(timestamp_key)
-        .context("Error reading creation metadata")?
-        .map(MaterializerStateIdentity)
-        .with_context(|| format!("disk state is missing `{}`", timestamp_key))?;
-
-    let materializer_state = match load_result {
-        Ok(s) => Some(s),
-        // We know path not found or version mismatch is normal, but some sqlite failures
-        // are worth logging here. TODO(scottcao): Refine our error types and figure out what
-        // errors to log
-        Err(_e) => None,
-    };
-    Ok((Some((db, identity)), materializer_state))
+    let materializer_state_path = paths.materializer_state_path();
+    let cache_failure_strategy = cache.failure_strategy;
+    let fs_for_delete = fs.dupe();
+    let io_executor_for_delete = io_executor.clone();
+    let io_executor_for_in_memory = io_executor.clone();
+
+    // Sibling of the real DB, used only when `CacheFailureStrategy::InMemory`
+    // kicks in: the primary path is known-bad at that point (open-retry and
+    // delete-and-recreate both just failed against it), so `InMemory`'s
+    // "behaves normally for the rest of this process" promise needs a fresh
+    // file elsewhere rather than trying that same path a third time. It's
+    // never read back on a later startup; this run's daemon is its only
+    // reader or writer.
+    let in_memory_fallback_path = materializer_state_path
+        .parent()
+        .context("materializer state path has no parent directory")?
+        .join(FileName::unchecked_new("materializer_state.in_memory_fallback"));
+
+    // Stat the DB directory before we touch it at all, so we have something to
+    // compare against once open/recovery finishes. `stat_materializer_state_mtime`
+    // returns `None` rather than erroring when there's nothing there yet (a
+    // fresh cache dir) or the stat fails for another reason; either way we
+    // simply can't vouch for the load below, which the `_ => false` arm of the
+    // mtime check treats as untrustworthy.
+    let pre_open_mtime = stat_materializer_state_mtime(&materializer_state_path);
+
+    // Everything from here down — the open/recover attempts, the schema
+    // migration they may trigger, and the metadata/version-table writes that
+    // `initialize` does once it has a connection — runs inside the spawned
+    // task below, not on this function's caller. `versions` and `metadata`
+    // themselves were already fully built above, synchronously, since that
+    // part is cheap; only the SQLite work is moved off the startup path. This
+    // function returns as soon as the task is spawned; the first caller that
+    // actually needs the DB awaits `MaterializerStateSqliteDbHandle::join`,
+    // which is a no-op wait if the task has already finished by then.
+    let task = tokio::task::spawn(async move {
+        // Most things in the rest of `metadata` should go in the metadata sqlite table.
+        // TODO(scottcao): Narrow down what metadata we need and and insert them into the
+        // metadata table before a feature rollout.
+        let (opened, recovery) = recover_disk_cache(
+            || {
+                MaterializerStateSqliteDb::initialize(
+                    materializer_state_path.clone(),
+                    versions.clone(),
+                    metadata.clone(),
+                    io_executor.clone(),
+                    digest_config,
+                )
+            },
+            || {
+                let fs = fs_for_delete.dupe();
+                let path = materializer_state_path.clone();
+                let io_executor = io_executor_for_delete.clone();
+                async move {
+                    io_executor
+                        .execute_io_inline(|| fs.remove_path_recursive(&path))
+                        .await
+                }
+            },
+            || {
+                MaterializerStateSqliteDb::initialize(
+                    in_memory_fallback_path.clone(),
+                    versions.clone(),
+                    metadata.clone(),
+                    io_executor_for_in_memory.clone(),
+                    digest_config,
+                )
+            },
+            cache_failure_strategy,
+        )
+        .await?;
+
+        // Stat again now that open/recovery has finished. If the directory's mtime
+        // moved (or either side is ambiguous) between the two looks, something
+        // wrote to it while we were opening it — e.g. a concurrent buck2
+        // daemon racing us on the same cache dir — and `load_result` can't be
+        // trusted to reflect the file we actually read.
+        let post_recovery_mtime = stat_materializer_state_mtime(&materializer_state_path);
+        let mtime_is_clean = match (pre_open_mtime, post_recovery_mtime) {
+            (Some(before), Some(after)) => {
+                validate_mtime(&before, &after) == MtimeValidation::Clean
+            }
+            _ => false,
+        };
+
+        let (db, identity, materializer_state) = match opened {
+            Some((mut db, load_result)) => {
+                let identity = db
+                    .created_by_table()
+                    .get(timestamp_key)
+                    .context("Error reading creation metadata")?
+                    .with_context(|| format!("disk state is missing `{}`", timestamp_key))
+                    .map(|identity| MaterializerStateIdentity { identity, recovery })?;
+
+                let materializer_state = match load_result {
+                    Ok(s) if mtime_is_clean => Some(s),
+                    // Either the sqlite load itself failed (path not found or
+                    // version mismatch is normal, but some sqlite failures are
+                    // worth logging here — TODO(scottcao): refine our error
+                    // types and figure out what errors to log), or it
+                    // succeeded but the DB directory's mtime was ambiguous or moved
+                    // while we were opening it, so the loaded state can't be
+                    // trusted this run.
+                    Ok(_) | Err(_) => None,
+                };
+                (Some(db), identity, materializer_state)
+            }
+            // Recovery fell back to `cache_failure_strategy` instead of producing a
+            // usable connection: there's no `created_by_table` to read identity from,
+            // so fall back to the timestamp generated for this initialization.
+            None => (
+                None,
+                MaterializerStateIdentity {
+                    identity: timestamp_on_initialization,
+                    recovery,
+                },
+                None,
+            ),
+        };
+        Ok((db, identity, materializer_state))
+    });
+
+    Ok(Some(MaterializerStateSqliteDbHandle { task }))
 }
 
 // Once we start storing disk state in the cache directory, we need to make sure
@@ -146,13 +557,19 @@ pub(crate) async fn maybe_initialize_materializer_sqlite_db(
 // state in buck2.
 // The following implements mitigation #2 by always deleting disk state.
 
-/// Recursively deletes all elements under `cache_dir_path`, except for known dirs
-/// listed in `known_dir_names`.
+/// Recursively deletes all elements under `cache_dir_path`, except for the
+/// directories of the caches registered in `registered_disk_caches`. The
+/// known-dir list is derived from that registry rather than passed in by
+/// hand, so every cache this build registers is kept without being listed at
+/// the call site.
 pub(crate) fn delete_unknown_disk_state(
     cache_dir_path: &AbsNormPath,
-    known_dir_names: &[&FileName],
+    options: &DiskStateOptions,
     fs: ProjectRoot,
 ) -> anyhow::Result<()> {
+    let caches = registered_disk_caches(options);
+    let known_dir_names: Vec<&FileName> = caches.iter().map(|cache| cache.name).collect();
+
     let res: anyhow::Result<()> = try {
         if cache_dir_path.exists() {
             for entry in fs_util::read_dir(cache_dir_path)? {
@@ -184,34 +601,41 @@ mod tests {
     use buck2_core::fs::paths::forward_rel_path::ForwardRelativePath;
     use buck2_core::fs::project::ProjectRootTemp;
     use buck2_core::fs::project_rel_path::ProjectRelativePath;
-    use dupe::Dupe;
 
     use super::*;
 
+    fn test_disk_state_options() -> DiskStateOptions {
+        DiskStateOptions {
+            sqlite_materializer_state: true,
+            cache_failure_strategy: CacheFailureStrategy::InMemory,
+        }
+    }
+
     #[test]
-    fn test_delete_all_from_cache_dir() {
+    fn test_delete_all_unregistered_dirs_from_cache_dir() {
         let fs_temp = ProjectRootTemp::new().unwrap();
         let fs = fs_temp.path();
         let cache_dir_path = fs.resolve(ProjectRelativePath::unchecked_new("buck-out/v2/cache"));
-        let materializer_state_db = cache_dir_path.join(ForwardRelativePath::unchecked_new(
-            "materializer_state/db.sqlite",
-        ));
         let command_hashes_db = cache_dir_path.join(ForwardRelativePath::unchecked_new(
             "command_hashes/db.sqlite",
         ));
-        fs.create_file(&materializer_state_db, false).unwrap();
+        let other_unknown_db = cache_dir_path.join(ForwardRelativePath::unchecked_new(
+            "some_future_cache/db.sqlite",
+        ));
         fs.create_file(&command_hashes_db, false).unwrap();
-        assert!(materializer_state_db.exists());
+        fs.create_file(&other_unknown_db, false).unwrap();
         assert!(command_hashes_db.exists());
+        assert!(other_unknown_db.exists());
 
-        delete_unknown_disk_state(&cache_dir_path, &[], fs.dupe()).unwrap();
+        // Neither dir matches a cache in `registered_disk_caches`, so both should go.
+        delete_unknown_disk_state(&cache_dir_path, &test_disk_state_options(), fs.dupe()).unwrap();
 
-        assert!(!materializer_state_db.exists());
         assert!(!command_hashes_db.exists());
+        assert!(!other_unknown_db.exists());
     }
 
     #[test]
-    fn test_delete_from_cache_dir_with_known_dirs() {
+    fn test_delete_from_cache_dir_preserves_registered_materializer_state_dir() {
         let fs_temp = ProjectRootTemp::new().unwrap();
         let fs = fs_temp.path();
         let cache_dir_path = fs.resolve(ProjectRelativePath::unchecked_new("buck-out/v2/cache"));
@@ -226,14 +650,281 @@ mod tests {
         assert!(materializer_state_db.exists());
         assert!(command_hashes_db.exists());
 
-        delete_unknown_disk_state(
-            &cache_dir_path,
-            &[FileName::unchecked_new("materializer_state")],
-            fs.dupe(),
-        )
-        .unwrap();
+        // `registered_disk_caches` always registers `materializer_state`, so it
+        // survives even though it wasn't passed in by hand.
+        delete_unknown_disk_state(&cache_dir_path, &test_disk_state_options(), fs.dupe()).unwrap();
 
         assert!(materializer_state_db.exists());
         assert!(!command_hashes_db.exists());
     }
+
+    #[test]
+    fn test_registered_disk_caches_tracks_materializer_state_options() {
+        let options = DiskStateOptions {
+            sqlite_materializer_state: true,
+            cache_failure_strategy: CacheFailureStrategy::BlackHole,
+        };
+        let caches = registered_disk_caches(&options);
+
+        assert_eq!(caches.len(), 1);
+        assert_eq!(caches[0].name, FileName::unchecked_new("materializer_state"));
+        assert_eq!(
+            caches[0].db_schema_version,
+            DB_SCHEMA_VERSION + MTIME_SECOND_AMBIGUOUS_SCHEMA_BUMP
+        );
+        assert_eq!(caches[0].failure_strategy, CacheFailureStrategy::BlackHole);
+    }
+
+    #[test]
+    fn test_cache_failure_strategy_from_str() {
+        assert_eq!(
+            "in_memory".parse::<CacheFailureStrategy>().unwrap(),
+            CacheFailureStrategy::InMemory
+        );
+        assert_eq!(
+            "black_hole".parse::<CacheFailureStrategy>().unwrap(),
+            CacheFailureStrategy::BlackHole
+        );
+        assert_eq!(
+            "error".parse::<CacheFailureStrategy>().unwrap(),
+            CacheFailureStrategy::Error
+        );
+        assert!("nonsense".parse::<CacheFailureStrategy>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_recover_disk_cache_clean_open() {
+        let (opened, recovery) = recover_disk_cache(
+            || async { Ok::<_, anyhow::Error>(42) },
+            || async { panic!("delete should not be called when open succeeds") },
+            || async { panic!("open_in_memory should not be called when open succeeds") },
+            CacheFailureStrategy::Error,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(opened, Some(42));
+        assert_eq!(recovery, CacheRecoveryOutcome::Clean);
+    }
+
+    #[tokio::test]
+    async fn test_recover_disk_cache_recovers_after_delete() {
+        let open_attempts = std::cell::Cell::new(0);
+        let deleted = std::cell::Cell::new(false);
+
+        let (opened, recovery) = recover_disk_cache(
+            || {
+                let attempt = open_attempts.get();
+                open_attempts.set(attempt + 1);
+                async move {
+                    if attempt < MAX_OPEN_ATTEMPTS {
+                        Err(anyhow::anyhow!("corrupt db"))
+                    } else {
+                        Ok(99)
+                    }
+                }
+            },
+            || {
+                deleted.set(true);
+                async { Ok(()) }
+            },
+            || async { panic!("open_in_memory should not be called when recreate succeeds") },
+            CacheFailureStrategy::Error,
+        )
+        .await
+        .unwrap();
+
+        assert!(deleted.get());
+        assert_eq!(opened, Some(99));
+        assert_eq!(recovery, CacheRecoveryOutcome::RecoveredFresh);
+    }
+
+    #[tokio::test]
+    async fn test_recover_disk_cache_black_hole_falls_back_to_none() {
+        let (opened, recovery) = recover_disk_cache(
+            || async { Err::<i32, _>(anyhow::anyhow!("still broken")) },
+            || async { Ok(()) },
+            || async { panic!("open_in_memory should not be called for BlackHole") },
+            CacheFailureStrategy::BlackHole,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(opened, None);
+        assert_eq!(
+            recovery,
+            CacheRecoveryOutcome::Fallback(CacheFailureStrategy::BlackHole)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_recover_disk_cache_in_memory_opens_a_real_fallback() {
+        let (opened, recovery) = recover_disk_cache(
+            || async { Err::<i32, _>(anyhow::anyhow!("still broken")) },
+            || async { Ok(()) },
+            || async { Ok(7) },
+            CacheFailureStrategy::InMemory,
+        )
+        .await
+        .unwrap();
+
+        // Unlike `BlackHole`, `InMemory` must hand back a real, usable value
+        // from `open_in_memory`, not a bare `None`.
+        assert_eq!(opened, Some(7));
+        assert_eq!(
+            recovery,
+            CacheRecoveryOutcome::Fallback(CacheFailureStrategy::InMemory)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_recover_disk_cache_in_memory_open_failure_propagates() {
+        let result = recover_disk_cache(
+            || async { Err::<i32, _>(anyhow::anyhow!("still broken")) },
+            || async { Ok(()) },
+            || async { Err::<i32, _>(anyhow::anyhow!("no memory left either")) },
+            CacheFailureStrategy::InMemory,
+        )
+        .await;
+
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_recover_disk_cache_error_strategy_propagates() {
+        let result = recover_disk_cache(
+            || async { Err::<i32, _>(anyhow::anyhow!("still broken")) },
+            || async { Ok(()) },
+            || async { panic!("open_in_memory should not be called for Error") },
+            CacheFailureStrategy::Error,
+        )
+        .await;
+
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_recover_disk_cache_delete_failure_routes_through_failure_strategy() {
+        // A delete failure is part of "delete-and-recreate failing" too: it
+        // must fall through to `failure_strategy` rather than propagating as
+        // a hard error that bypasses a user's configured `BlackHole`/`InMemory`
+        // strategy.
+        let (opened, recovery) = recover_disk_cache(
+            || async { Err::<i32, _>(anyhow::anyhow!("still broken")) },
+            || async { Err(anyhow::anyhow!("permission denied")) },
+            || async { panic!("open_in_memory should not be called for BlackHole") },
+            CacheFailureStrategy::BlackHole,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(opened, None);
+        assert_eq!(
+            recovery,
+            CacheRecoveryOutcome::Fallback(CacheFailureStrategy::BlackHole)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_recover_disk_cache_delete_failure_with_error_strategy_propagates() {
+        let result = recover_disk_cache(
+            || async { Err::<i32, _>(anyhow::anyhow!("still broken")) },
+            || async { Err(anyhow::anyhow!("permission denied")) },
+            || async { panic!("open_in_memory should not be called for Error") },
+            CacheFailureStrategy::Error,
+        )
+        .await;
+
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_handle_join_propagates_background_task_failure() {
+        let task = tokio::task::spawn(async {
+            Err::<
+                (
+                    Option<MaterializerStateSqliteDb>,
+                    MaterializerStateIdentity,
+                    Option<MaterializerState>,
+                ),
+                _,
+            >(anyhow::anyhow!("background init failed"))
+        });
+        let handle = MaterializerStateSqliteDbHandle { task };
+
+        let err = handle.join().await.unwrap_err();
+
+        assert!(err.to_string().contains("background init failed"));
+    }
+
+    #[test]
+    fn test_truncated_timestamp_marks_same_second_write_ambiguous() {
+        let t = UNIX_EPOCH + std::time::Duration::from_secs(1_000);
+        let observed_same_second = UNIX_EPOCH + std::time::Duration::from_millis(1_000_500);
+
+        let ts = TruncatedTimestamp::new(t, observed_same_second).unwrap();
+
+        assert_eq!(ts.seconds, 1_000);
+        assert!(ts.second_ambiguous);
+        assert!(!ts.is_trustworthy());
+    }
+
+    #[test]
+    fn test_truncated_timestamp_trusts_a_prior_second() {
+        let t = UNIX_EPOCH + std::time::Duration::from_secs(1_000);
+        let observed_later = UNIX_EPOCH + std::time::Duration::from_secs(1_005);
+
+        let ts = TruncatedTimestamp::new(t, observed_later).unwrap();
+
+        assert!(!ts.second_ambiguous);
+        assert!(ts.is_trustworthy());
+    }
+
+    #[test]
+    fn test_validate_mtime_clean_when_trustworthy_and_equal() {
+        let recorded = TruncatedTimestamp {
+            seconds: 1_000,
+            nanoseconds: 0,
+            second_ambiguous: false,
+        };
+        let on_disk = recorded;
+
+        assert_eq!(validate_mtime(&recorded, &on_disk), MtimeValidation::Clean);
+    }
+
+    #[test]
+    fn test_validate_mtime_falls_back_to_digest_when_ambiguous() {
+        let recorded = TruncatedTimestamp {
+            seconds: 1_000,
+            nanoseconds: 0,
+            second_ambiguous: true,
+        };
+        let on_disk = recorded;
+
+        // Even though the two timestamps are bit-for-bit equal, the recorded one
+        // is ambiguous, so it must never be trusted on its own.
+        assert_eq!(
+            validate_mtime(&recorded, &on_disk),
+            MtimeValidation::NeedsDigestCheck
+        );
+    }
+
+    #[test]
+    fn test_validate_mtime_falls_back_to_digest_when_mismatched() {
+        let recorded = TruncatedTimestamp {
+            seconds: 1_000,
+            nanoseconds: 0,
+            second_ambiguous: false,
+        };
+        let on_disk = TruncatedTimestamp {
+            seconds: 1_001,
+            nanoseconds: 0,
+            second_ambiguous: false,
+        };
+
+        assert_eq!(
+            validate_mtime(&recorded, &on_disk),
+            MtimeValidation::NeedsDigestCheck
+        );
+    }
 }